@@ -18,26 +18,152 @@ pub struct Args {
     #[clap(long, default_value = "3")]
     pub upstream_timeout: u64,
 
-    /// List of upstream DNS servers to try, separated by commas, in the format "address[:port][/protocol]".
-    /// By default, the port is 53 and the protocol is udp
+    /// List of upstream DNS servers to try, separated by commas, in the format "address[:port][/protocol[#name]]".
+    /// "address" may be an IP literal or a hostname; hostnames are resolved once at startup.
+    /// By default, the port is 53 and the protocol is udp.
+    /// The encrypted protocols (tls, https, quic) default to their standard ports (853, 443, 443)
+    /// and accept a "#name" suffix giving the TLS/SNI server name to validate the upstream's certificate against,
+    /// e.g. "1.1.1.1:853/tls#cloudflare-dns.com" or "8.8.8.8/https#dns.google/dns-query".
+    /// The protocol may also carry a "4" or "6" suffix (e.g. "/tcp4", "/udp6") to restrict a
+    /// hostname's resolved addresses to one address family.
     #[clap(short, long, value_delimiter = ',', num_args = 1.., required = true)]
     pub upstream_servers: Vec<UpstreamSpec>,
+
+    /// Validate DNSSEC signature chains on upstream responses, and return SERVFAIL instead of
+    /// forwarding a response whose signatures don't check out.
+    /// An answer only has the AD (authentic data) bit set if it was actually signed and
+    /// validated; this is remembered alongside the cached records, so replaying a cached
+    /// answer doesn't claim authentication it didn't earn.
+    #[clap(long)]
+    pub dnssec: bool,
+
+    /// Path to a domain filtering rules file (see [`crate::filter::RuleSet::load`] for the
+    /// format). Filtered queries are answered directly, without going to the upstream resolver.
+    #[clap(long)]
+    pub rules: Option<std::path::PathBuf>,
+
+    /// Maximum number of queries to process concurrently. Once this many requests are
+    /// in flight, further ones wait for one to finish rather than piling onto the
+    /// upstream resolver and the database unbounded.
+    #[clap(long, default_value = "256")]
+    pub max_inflight: usize,
+
+    /// When a cached record's TTL has expired but it's still present, serve it anyway with
+    /// this TTL (in seconds) while refreshing it from upstream in the background, instead of
+    /// blocking the response on upstream (RFC 8767 serve-stale).
+    #[clap(long, default_value = "30")]
+    pub serve_stale_ttl: u32,
+
+    /// Proactively refresh a cached record from upstream in the background, in addition to
+    /// answering from the cache, once its remaining TTL (in seconds) drops below this threshold.
+    #[clap(long, default_value = "60")]
+    pub prefetch_threshold: u32,
+}
+
+/// An upstream server's address: either an IP literal, used as-is, or a hostname,
+/// which is resolved once at startup (see [`UpstreamSpec::resolve`]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Host {
+    Ip(IpAddr),
+    Name(String),
+}
+
+/// Restricts hostname resolution to one address family, via the `4`/`6` protocol suffix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
 }
 
 #[derive(Clone)]
 pub struct UpstreamSpec {
-    pub host: IpAddr,
+    pub host: Host,
     pub port: u16,
     pub protocol: hickory_resolver::config::Protocol,
+    /// The TLS/SNI server name to validate the upstream's certificate against.
+    /// Required (in practice) for the `tls`, `https` and `quic` protocols, since those
+    /// authenticate the upstream by name rather than by IP address.
+    pub tls_dns_name: Option<String>,
+    /// The HTTP path to query, for the `https` protocol. Defaults to `/dns-query` when unset.
+    pub http_endpoint: Option<String>,
+    /// Restricts a hostname's resolved addresses to this family, if given.
+    /// Has no effect on IP-literal hosts.
+    pub family: Option<AddressFamily>,
 }
 
 impl UpstreamSpec {
+    /// The fast path for an IP-literal host: no resolution needed.
+    ///
+    /// # Panics
+    /// Panics if `self.host` is a [`Host::Name`]; use [`UpstreamSpec::resolve`] for those.
     pub fn to_name_server_config(&self) -> NameServerConfig {
-        NameServerConfig::new(
-            std::net::SocketAddr::new(self.host, self.port),
-            self.protocol,
-        )
+        let Host::Ip(ip) = self.host else {
+            unreachable!("to_name_server_config only supports IP-literal hosts, use resolve()")
+        };
+        self.name_server_config_for(ip)
+    }
+
+    /// Resolves this spec into one or more concrete name server configs.
+    ///
+    /// IP-literal hosts resolve trivially. Hostnames are looked up via the OS resolver,
+    /// filtered down to the requested address family (if any), and ordered
+    /// Happy-Eyeballs-style (alternating address families) so that, when a hostname has
+    /// both A and AAAA records, a reachable address is likely to be tried first.
+    pub async fn resolve(&self) -> Result<Vec<NameServerConfig>, String> {
+        let ip = match &self.host {
+            Host::Ip(ip) => return Ok(vec![self.name_server_config_for(*ip)]),
+            Host::Name(name) => name,
+        };
+
+        let resolved: Vec<IpAddr> = tokio::net::lookup_host((ip.as_str(), self.port))
+            .await
+            .map_err(|e| format!("Failed to resolve upstream host {ip}: {e}"))?
+            .map(|addr| addr.ip())
+            .filter(|addr| match self.family {
+                Some(AddressFamily::V4) => addr.is_ipv4(),
+                Some(AddressFamily::V6) => addr.is_ipv6(),
+                None => true,
+            })
+            .collect();
+
+        let resolved = happy_eyeballs_order(resolved);
+        if resolved.is_empty() {
+            return Err(format!("Upstream host {ip} resolved to no usable addresses"));
+        }
+
+        Ok(resolved
+            .into_iter()
+            .map(|ip| self.name_server_config_for(ip))
+            .collect())
     }
+
+    fn name_server_config_for(&self, ip: IpAddr) -> NameServerConfig {
+        let mut config =
+            NameServerConfig::new(std::net::SocketAddr::new(ip, self.port), self.protocol);
+        config.tls_dns_name = self.tls_dns_name.clone();
+        if self.protocol == hickory_resolver::config::Protocol::Https {
+            config.http_endpoint = self.http_endpoint.clone();
+        }
+        config
+    }
+}
+
+/// Interleaves IPv6 and IPv4 addresses (v6 first), Happy-Eyeballs-style, so that trying
+/// candidates in order gives a working address a good chance of coming up early
+/// regardless of which family is actually reachable.
+fn happy_eyeballs_order(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    let (mut v6, mut v4): (Vec<IpAddr>, Vec<IpAddr>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        if !v6.is_empty() {
+            ordered.push(v6.remove(0));
+        }
+        if !v4.is_empty() {
+            ordered.push(v4.remove(0));
+        }
+    }
+    ordered
 }
 
 impl FromStr for UpstreamSpec {
@@ -57,48 +183,121 @@ impl FromStr for UpstreamSpec {
         }
 
         let host = s[..first_separator].to_string();
-        if host.is_empty() {
-            return Err("No host specified".to_string());
-        }
-        let host: IpAddr = host
-            .parse()
-            .map_err(|_| format!("Failed to parse IP: {host}"))?;
 
-        let mut port = 53;
+        let mut port = None;
         let mut protocol = hickory_resolver::config::Protocol::Udp;
+        let mut tls_dns_name = None;
+        let mut http_endpoint = None;
+        let mut family = None;
 
         if first_separator == s.len() {
-            // No port or protocol specified
+            // No port or protocol specified, so there's no "#name" to fall back on either
+            if host.is_empty() {
+                return Err("No host specified".to_string());
+            }
+            let host = match host.parse::<IpAddr>() {
+                Ok(ip) => Host::Ip(ip),
+                Err(_) => Host::Name(host),
+            };
             return Ok(UpstreamSpec {
                 host,
-                port,
+                port: 53,
                 protocol,
+                tls_dns_name,
+                http_endpoint,
+                family,
             });
         }
 
         if let Some(idx) = s.find('/') {
             let proto = &s[idx + 1..];
+
+            // A protocol that authenticates the upstream by name may carry a "#name[/path]" suffix
+            let (proto, name_part) = match proto.find('#') {
+                Some(hash_idx) => (&proto[..hash_idx], Some(&proto[hash_idx + 1..])),
+                None => (proto, None),
+            };
+
+            // A plaintext protocol may carry a "4" or "6" address-family suffix
+            let proto = match proto.strip_suffix('4') {
+                Some(base) => {
+                    family = Some(AddressFamily::V4);
+                    base
+                }
+                None => match proto.strip_suffix('6') {
+                    Some(base) => {
+                        family = Some(AddressFamily::V6);
+                        base
+                    }
+                    None => proto,
+                },
+            };
+
             match proto {
                 "tcp" => protocol = hickory_resolver::config::Protocol::Tcp,
                 "udp" => protocol = hickory_resolver::config::Protocol::Udp,
+                "tls" => protocol = hickory_resolver::config::Protocol::Tls,
+                "https" => protocol = hickory_resolver::config::Protocol::Https,
+                "quic" => protocol = hickory_resolver::config::Protocol::Quic,
                 _ => return Err(format!("Unknown protocol: {proto}")),
             }
 
+            if let Some(name_part) = name_part {
+                match protocol {
+                    hickory_resolver::config::Protocol::Https => {
+                        match name_part.find('/') {
+                            Some(slash_idx) => {
+                                tls_dns_name = Some(name_part[..slash_idx].to_string());
+                                http_endpoint = Some(name_part[slash_idx..].to_string());
+                            }
+                            None => tls_dns_name = Some(name_part.to_string()),
+                        }
+                    }
+                    _ => tls_dns_name = Some(name_part.to_string()),
+                }
+            }
+
             // Now that we've extracted the protocol, remove it from the string
             s = &s[..idx];
         }
 
         if let Some(idx) = s.find(':') {
             let port_str = &s[idx + 1..];
-            port = port_str
-                .parse::<u16>()
-                .map_err(|_| format!("Failed to parse port: {port_str}"))?;
+            port = Some(
+                port_str
+                    .parse::<u16>()
+                    .map_err(|_| format!("Failed to parse port: {port_str}"))?,
+            );
         }
 
+        let port = port.unwrap_or(match protocol {
+            hickory_resolver::config::Protocol::Tls => 853,
+            hickory_resolver::config::Protocol::Https
+            | hickory_resolver::config::Protocol::Quic => 443,
+            _ => 53,
+        });
+
+        // A host-less spec (e.g. "/https#dns.google/dns-query") resolves purely from the
+        // "#name" suffix, since that's the only thing identifying the upstream at all.
+        let host = if host.is_empty() {
+            match &tls_dns_name {
+                Some(name) => Host::Name(name.clone()),
+                None => return Err("No host specified".to_string()),
+            }
+        } else {
+            match host.parse::<IpAddr>() {
+                Ok(ip) => Host::Ip(ip),
+                Err(_) => Host::Name(host),
+            }
+        };
+
         Ok(UpstreamSpec {
             host,
             port,
             protocol,
+            tls_dns_name,
+            http_endpoint,
+            family,
         })
     }
 }
@@ -110,27 +309,27 @@ mod tests {
     #[test]
     fn test_upstream_spec() {
         let spec: UpstreamSpec = "127.0.0.1".parse().unwrap();
-        assert_eq!(spec.host, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(spec.host, Host::Ip("127.0.0.1".parse().unwrap()));
         assert_eq!(spec.port, 53);
         assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Udp);
 
         let spec: UpstreamSpec = "127.0.0.1:80".parse().unwrap();
-        assert_eq!(spec.host, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(spec.host, Host::Ip("127.0.0.1".parse().unwrap()));
         assert_eq!(spec.port, 80);
         assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Udp);
 
         let spec: UpstreamSpec = "127.0.0.1:80/udp".parse().unwrap();
-        assert_eq!(spec.host, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(spec.host, Host::Ip("127.0.0.1".parse().unwrap()));
         assert_eq!(spec.port, 80);
         assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Udp);
 
         let spec: UpstreamSpec = "127.0.0.1/tcp".parse().unwrap();
-        assert_eq!(spec.host, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(spec.host, Host::Ip("127.0.0.1".parse().unwrap()));
         assert_eq!(spec.port, 53);
         assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Tcp);
 
         let spec: UpstreamSpec = "127.0.0.1:80/tcp".parse().unwrap();
-        assert_eq!(spec.host, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(spec.host, Host::Ip("127.0.0.1".parse().unwrap()));
         assert_eq!(spec.port, 80);
         assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Tcp);
     }
@@ -143,6 +342,72 @@ mod tests {
         assert!("127.0.0.1:80/wtf".parse::<UpstreamSpec>().is_err());
         assert!("127.0.0.1:80/udp:80".parse::<UpstreamSpec>().is_err());
         assert!("127.0.0.1:80/udp/udp".parse::<UpstreamSpec>().is_err());
-        assert!("example.com".parse::<UpstreamSpec>().is_err());
+    }
+
+    #[test]
+    fn test_upstream_spec_encrypted() {
+        let spec: UpstreamSpec = "1.1.1.1:853/tls#cloudflare-dns.com".parse().unwrap();
+        assert_eq!(spec.host, Host::Ip("1.1.1.1".parse().unwrap()));
+        assert_eq!(spec.port, 853);
+        assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Tls);
+        assert_eq!(spec.tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+
+        let spec: UpstreamSpec = "1.1.1.1/tls#cloudflare-dns.com".parse().unwrap();
+        assert_eq!(spec.port, 853);
+
+        let spec: UpstreamSpec = "8.8.8.8/https#dns.google".parse().unwrap();
+        assert_eq!(spec.port, 443);
+        assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Https);
+        assert_eq!(spec.tls_dns_name.as_deref(), Some("dns.google"));
+        assert_eq!(spec.http_endpoint, None);
+
+        let spec: UpstreamSpec = "8.8.8.8/https#dns.google/dns-query".parse().unwrap();
+        assert_eq!(spec.tls_dns_name.as_deref(), Some("dns.google"));
+        assert_eq!(spec.http_endpoint.as_deref(), Some("/dns-query"));
+
+        let spec: UpstreamSpec = "9.9.9.9/quic#dns.quad9.net".parse().unwrap();
+        assert_eq!(spec.port, 443);
+        assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Quic);
+    }
+
+    #[test]
+    fn test_upstream_spec_host_less() {
+        // A bare "#name" suffix with no address at all resolves via the name itself.
+        let spec: UpstreamSpec = "/https#dns.google/dns-query".parse().unwrap();
+        assert_eq!(spec.host, Host::Name("dns.google".to_string()));
+        assert_eq!(spec.port, 443);
+        assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Https);
+        assert_eq!(spec.tls_dns_name.as_deref(), Some("dns.google"));
+        assert_eq!(spec.http_endpoint.as_deref(), Some("/dns-query"));
+
+        // Without a "#name" to fall back on, a host-less spec is still an error.
+        assert!("/tcp".parse::<UpstreamSpec>().is_err());
+    }
+
+    #[test]
+    fn test_upstream_spec_hostname() {
+        let spec: UpstreamSpec = "dns.quad9.net".parse().unwrap();
+        assert_eq!(spec.host, Host::Name("dns.quad9.net".to_string()));
+        assert_eq!(spec.port, 53);
+        assert_eq!(spec.family, None);
+
+        let spec: UpstreamSpec = "dns.quad9.net/tcp4".parse().unwrap();
+        assert_eq!(spec.host, Host::Name("dns.quad9.net".to_string()));
+        assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Tcp);
+        assert_eq!(spec.family, Some(AddressFamily::V4));
+
+        let spec: UpstreamSpec = "dns.quad9.net/udp6".parse().unwrap();
+        assert_eq!(spec.protocol, hickory_resolver::config::Protocol::Udp);
+        assert_eq!(spec.family, Some(AddressFamily::V6));
+    }
+
+    #[test]
+    fn test_happy_eyeballs_order() {
+        let v4a: IpAddr = "1.1.1.1".parse().unwrap();
+        let v4b: IpAddr = "1.0.0.1".parse().unwrap();
+        let v6a: IpAddr = "2606:4700:4700::1111".parse().unwrap();
+
+        assert_eq!(happy_eyeballs_order(vec![v4a, v4b, v6a]), vec![v6a, v4a, v4b]);
+        assert_eq!(happy_eyeballs_order(vec![]), Vec::<IpAddr>::new());
     }
 }
@@ -0,0 +1,72 @@
+//! The dedicated cache-writer task: upstream answers are queued here instead of spawning a
+//! task per query, so a flood of queries can't spawn unbounded writers onto the SQLite pool.
+
+use tokio::sync::mpsc;
+
+/// How many pending writer jobs will be buffered before new ones are dropped.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A cache upsert, queued for the writer task.
+pub struct UpsertJob {
+    pub record_name: String,
+    pub record_type: String,
+    pub content_json: String,
+    pub data_received_at_unix: i64,
+    pub last_query_at_unix: i64,
+    /// Whether this record set was actually DNSSEC-validated when it was fetched, as opposed to
+    /// `--dnssec` merely being on at the time. Replayed on every cache hit instead of the current
+    /// `--dnssec` setting, so a stale cache entry can't claim authentication it didn't earn.
+    pub authentic_data: bool,
+}
+
+/// Bumps `last_query_at_unix` on an existing entry, queued for the writer task. A cache hit only
+/// needs to touch this bookkeeping column, so it's split out from [`UpsertJob`] to keep the
+/// on-path cache check a plain read instead of a write.
+pub struct TouchJob {
+    pub record_name: String,
+    pub record_type: String,
+    pub last_query_at_unix: i64,
+}
+
+/// A job for the writer task.
+pub enum WriterJob {
+    Upsert(UpsertJob),
+    Touch(TouchJob),
+}
+
+/// Spawns the single long-lived task that applies cache writes to the database, and returns
+/// a sender for queuing jobs onto it.
+pub fn spawn_writer(db: sqlx::SqlitePool) -> mpsc::Sender<WriterJob> {
+    let (tx, mut rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    tokio::spawn(async move {
+        while let Some(job) = rx.recv().await {
+            match job {
+                WriterJob::Upsert(job) => {
+                    sqlx::query!("INSERT INTO record (record_name, record_type, content_json, data_received_at_unix, last_query_at_unix, authentic_data) VALUES (?,?,?,?,?,?) ON CONFLICT (record_name, record_type) DO UPDATE SET content_json = ?, data_received_at_unix = ?, last_query_at_unix = ?, authentic_data = ?",
+                        job.record_name,
+                        job.record_type,
+                        job.content_json,
+                        job.data_received_at_unix,
+                        job.last_query_at_unix,
+                        job.authentic_data,
+                        job.content_json,
+                        job.data_received_at_unix,
+                        job.last_query_at_unix,
+                        job.authentic_data,
+                    ).execute(&db).await.expect("Failed to insert record");
+                }
+                WriterJob::Touch(job) => {
+                    sqlx::query!(
+                        "UPDATE record SET last_query_at_unix = ? WHERE record_name = ? AND record_type = ?",
+                        job.last_query_at_unix,
+                        job.record_name,
+                        job.record_type,
+                    ).execute(&db).await.expect("Failed to touch record");
+                }
+            }
+        }
+    });
+
+    tx
+}
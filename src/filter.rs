@@ -0,0 +1,190 @@
+//! Domain filtering ("ad blocking") rules, matched by most-specific-suffix.
+
+use std::{collections::HashMap, net::IpAddr, path::Path};
+
+/// What to do with a query matching a filter rule.
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// Refuse the query outright, as if the domain didn't exist.
+    Block,
+    /// Answer with a fixed address instead of going to the upstream resolver.
+    Sinkhole(IpAddr),
+    /// Explicit passthrough. Wins over a broader `Block` rule covering the same name.
+    Allow,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    action: Option<Action>,
+}
+
+/// A reversed-label suffix tree of filtering rules.
+///
+/// A rule on `ads.example.com` is stored along the path `com -> example -> ads`, so looking
+/// up `sub.ads.example.com` walks the same path and inherits the `ads.example.com` rule (and
+/// so does everything else under it) unless a more specific rule further down overrides it.
+#[derive(Default)]
+pub struct RuleSet {
+    root: Node,
+}
+
+impl RuleSet {
+    /// Loads rules from a file with one rule per line:
+    /// `block <domain>`, `allow <domain>` or `sinkhole <address> <domain>`.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut rules = RuleSet::default();
+
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line_num = lineno + 1;
+            let mut parts = line.split_whitespace();
+            let action = parts
+                .next()
+                .unwrap_or_else(|| panic!("{}:{line_num}: empty rule", path.display()));
+
+            match action {
+                "block" => {
+                    let domain = parts.next().unwrap_or_else(|| {
+                        panic!("{}:{line_num}: `block` needs a domain", path.display())
+                    });
+                    rules.insert(domain, Action::Block);
+                }
+                "allow" => {
+                    let domain = parts.next().unwrap_or_else(|| {
+                        panic!("{}:{line_num}: `allow` needs a domain", path.display())
+                    });
+                    rules.insert(domain, Action::Allow);
+                }
+                "sinkhole" => {
+                    let address = parts.next().unwrap_or_else(|| {
+                        panic!(
+                            "{}:{line_num}: `sinkhole` needs an address and a domain",
+                            path.display()
+                        )
+                    });
+                    let address: IpAddr = address.parse().unwrap_or_else(|_| {
+                        panic!("{}:{line_num}: invalid sinkhole address {address}", path.display())
+                    });
+                    let domain = parts.next().unwrap_or_else(|| {
+                        panic!(
+                            "{}:{line_num}: `sinkhole` needs an address and a domain",
+                            path.display()
+                        )
+                    });
+                    rules.insert(domain, Action::Sinkhole(address));
+                }
+                other => panic!("{}:{line_num}: unknown rule action {other:?}", path.display()),
+            }
+        }
+
+        Ok(rules)
+    }
+
+    fn insert(&mut self, domain: &str, action: Action) {
+        let mut node = &mut self.root;
+        for label in domain.trim_end_matches('.').rsplit('.') {
+            node = node
+                .children
+                .entry(label.to_ascii_lowercase())
+                .or_default();
+        }
+        node.action = Some(action);
+    }
+
+    /// Finds the most specific rule covering `name`, if any, walking labels from the TLD inward.
+    ///
+    /// DNS names are case-insensitive, so labels are normalized before lookup; otherwise a rule
+    /// on `ads.example.com` wouldn't catch a 0x20-mixed-case query for `ADS.example.com`.
+    pub fn lookup(&self, name: &str) -> Option<Action> {
+        let mut node = &self.root;
+        let mut matched = None;
+        for label in name.trim_end_matches('.').rsplit('.') {
+            match node.children.get(&label.to_ascii_lowercase()) {
+                Some(next) => node = next,
+                None => break,
+            }
+            if let Some(action) = node.action {
+                matched = Some(action);
+            }
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &[&str]) -> RuleSet {
+        let mut rules = RuleSet::default();
+        for line in lines {
+            let mut parts = line.split_whitespace();
+            match parts.next().unwrap() {
+                "block" => rules.insert(parts.next().unwrap(), Action::Block),
+                "allow" => rules.insert(parts.next().unwrap(), Action::Allow),
+                "sinkhole" => {
+                    let addr = parts.next().unwrap().parse().unwrap();
+                    rules.insert(parts.next().unwrap(), Action::Sinkhole(addr));
+                }
+                _ => unreachable!(),
+            }
+        }
+        rules
+    }
+
+    #[test]
+    fn test_block_covers_subdomains() {
+        let rules = rules(&["block ads.example.com"]);
+        assert!(matches!(
+            rules.lookup("ads.example.com").unwrap(),
+            Action::Block
+        ));
+        assert!(matches!(
+            rules.lookup("tracker.ads.example.com").unwrap(),
+            Action::Block
+        ));
+        assert!(rules.lookup("example.com").is_none());
+        assert!(rules.lookup("other.com").is_none());
+    }
+
+    #[test]
+    fn test_allow_overrides_broader_block() {
+        let rules = rules(&["block ads.example.com", "allow good.ads.example.com"]);
+        assert!(matches!(
+            rules.lookup("good.ads.example.com").unwrap(),
+            Action::Allow
+        ));
+        assert!(matches!(
+            rules.lookup("bad.ads.example.com").unwrap(),
+            Action::Block
+        ));
+    }
+
+    #[test]
+    fn test_lookup_is_case_insensitive() {
+        let rules = rules(&["block ads.example.com"]);
+        assert!(matches!(
+            rules.lookup("ADS.example.com").unwrap(),
+            Action::Block
+        ));
+        assert!(matches!(
+            rules.lookup("tracker.Ads.Example.COM").unwrap(),
+            Action::Block
+        ));
+    }
+
+    #[test]
+    fn test_sinkhole() {
+        let rules = rules(&["sinkhole 0.0.0.0 ads.example.com"]);
+        assert!(matches!(
+            rules.lookup("ads.example.com").unwrap(),
+            Action::Sinkhole(addr) if addr == "0.0.0.0".parse::<IpAddr>().unwrap()
+        ));
+    }
+}
@@ -1,8 +1,17 @@
 mod args;
+mod cache;
+mod filter;
 
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::Semaphore;
 
-use hickory_client::rr::{Record, RecordData};
+use hickory_client::rr::{rdata, RData, Record, RecordData, RecordType};
 use hickory_resolver::{
     config::{ResolverConfig, ResolverOpts},
     name_server::{GenericConnector, TokioRuntimeProvider},
@@ -35,14 +44,41 @@ async fn main() {
 
     let mut config = ResolverConfig::new();
     for server in args.upstream_servers {
-        config.add_name_server(server.to_name_server_config());
+        match &server.host {
+            args::Host::Ip(_) => config.add_name_server(server.to_name_server_config()),
+            args::Host::Name(name) => {
+                let resolved = server
+                    .resolve()
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to resolve upstream server {name}: {e}"));
+                for name_server in resolved {
+                    config.add_name_server(name_server);
+                }
+            }
+        }
     }
     let mut opts = ResolverOpts::default();
     opts.timeout = Duration::from_secs(args.upstream_timeout);
+    opts.validate = args.dnssec;
+    opts.edns0 = args.dnssec;
+
+    let rules = args
+        .rules
+        .as_deref()
+        .map(|path| filter::RuleSet::load(path).expect("Failed to load filtering rules"));
+
+    let cache_writer = cache::spawn_writer(db.clone());
 
     let handler = Handler {
         resolver: hickory_resolver::AsyncResolver::tokio(config, opts),
         db,
+        dnssec: args.dnssec,
+        rules,
+        cache_writer,
+        inflight: Arc::new(Semaphore::new(args.max_inflight)),
+        serve_stale_ttl: args.serve_stale_ttl,
+        prefetch_threshold: args.prefetch_threshold,
+        refreshing: Arc::new(Mutex::new(HashSet::new())),
     };
     let mut srv = ServerFuture::new(handler);
 
@@ -64,6 +100,98 @@ async fn main() {
 struct Handler {
     pub resolver: hickory_resolver::AsyncResolver<GenericConnector<TokioRuntimeProvider>>,
     pub db: sqlx::SqlitePool,
+    /// Whether DNSSEC validation is enabled. Doesn't by itself mean an answer is authentic: see
+    /// [`is_authentic`] for the per-answer check actually used to set the AD bit.
+    pub dnssec: bool,
+    /// Domain filtering rules, if configured. Checked before the upstream lookup and before any
+    /// cache interaction.
+    pub rules: Option<filter::RuleSet>,
+    /// Sender for the dedicated cache-writer task; upstream answers are queued here instead of
+    /// spawning a task per query.
+    pub cache_writer: tokio::sync::mpsc::Sender<cache::WriterJob>,
+    /// Bounds how many queries are processed concurrently.
+    pub inflight: Arc<Semaphore>,
+    /// TTL (in seconds) given to a cached answer served after its real TTL expired, while a
+    /// background refresh is in flight.
+    pub serve_stale_ttl: u32,
+    /// Remaining-TTL threshold (in seconds) below which a fresh cache hit also triggers a
+    /// background refresh, so the entry doesn't go stale before the next query for it arrives.
+    pub prefetch_threshold: u32,
+    /// `(record_name, record_type)` keys with a background refresh currently in flight, so a
+    /// flood of queries for one hot stale/near-expiry name coalesces into a single upstream
+    /// lookup instead of spawning one task per request.
+    pub refreshing: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl Handler {
+    /// Re-queries upstream for `(name, record_type)` in the background and, on success, updates
+    /// the cache. Used for serve-stale and prefetch: the client has already gotten (or is about
+    /// to get) an answer from the cache, so this doesn't need to report failures anywhere but
+    /// the logs.
+    fn spawn_background_refresh(&self, name: hickory_client::rr::Name, record_type: RecordType) {
+        // Coalesce concurrent refreshes of the same key: a flood of queries against one hot
+        // stale/near-expiry name should trigger one upstream lookup, not one per request.
+        let key = (name.to_string(), record_type.to_string());
+        if !self
+            .refreshing
+            .lock()
+            .expect("refreshing set lock poisoned")
+            .insert(key.clone())
+        {
+            tracing::debug!("Background refresh of {} {} already in flight", key.0, key.1);
+            return;
+        }
+
+        let resolver = self.resolver.clone();
+        let cache_writer = self.cache_writer.clone();
+        let dnssec = self.dnssec;
+        let inflight = self.inflight.clone();
+        let refreshing = self.refreshing.clone();
+
+        tokio::spawn(async move {
+            // Also gate through the same semaphore as regular queries, so a stale name with a
+            // lot of in-flight clients can't fan out unbounded upstream lookups of its own.
+            let _permit = inflight
+                .acquire_owned()
+                .await
+                .expect("Inflight semaphore should never be closed");
+
+            match resolver.lookup(name.clone(), record_type).await {
+                Ok(data) => {
+                    let record_name = name.to_string();
+                    let record_type = record_type.to_string();
+                    let records = data.record_iter().collect::<Vec<_>>();
+                    let authentic_data = is_authentic(dnssec, records.iter().copied());
+                    let content_json = serde_json::to_string(&records)
+                        .expect("failed to serialize record data");
+                    let now = now_unix();
+
+                    if let Err(e) =
+                        cache_writer.try_send(cache::WriterJob::Upsert(cache::UpsertJob {
+                            record_name: record_name.clone(),
+                            record_type: record_type.clone(),
+                            content_json,
+                            data_received_at_unix: now,
+                            last_query_at_unix: now,
+                            authentic_data,
+                        }))
+                    {
+                        tracing::warn!(
+                            "Cache writer is falling behind, dropping background refresh of {record_name} {record_type}: {e}"
+                        );
+                    }
+                }
+                Err(why) => {
+                    tracing::warn!("Background refresh of {name} {record_type} failed: {why}");
+                }
+            }
+
+            refreshing
+                .lock()
+                .expect("refreshing set lock poisoned")
+                .remove(&key);
+        });
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -74,23 +202,38 @@ struct RecordsByKind {
     pub additionals: Vec<Record>,
 }
 
+/// How to set the TTL of a cached record when replaying it in a response.
+enum TtlPolicy {
+    /// Compute the TTL remaining relative to when the record was received from upstream.
+    RelativeTo(std::time::SystemTime),
+    /// Force every record to this TTL, ignoring how much of its real TTL is left.
+    /// Used for serve-stale answers, where the real TTL has already run out.
+    Capped(u32),
+}
+
 fn sort_out_records(
     request: &Request,
     records: &[Record],
-    received_at: std::time::SystemTime,
+    ttl_policy: TtlPolicy,
 ) -> RecordsByKind {
     let mut d = RecordsByKind::default();
     for record in records {
-        // Update the TTL of the record
-        // The TTL in the record is correct for the time of the query,
-        // so we need to update it to be relative to the current time
         let mut record = record.clone();
-        let ttl = record.ttl();
-        let ttl_expires_at = received_at + std::time::Duration::from_secs(ttl as u64);
-        let time_until_expiration = ttl_expires_at
-            .duration_since(std::time::SystemTime::now())
-            .unwrap_or_default();
-        record.set_ttl(time_until_expiration.as_secs() as u32);
+
+        match ttl_policy {
+            TtlPolicy::RelativeTo(received_at) => {
+                // Update the TTL of the record
+                // The TTL in the record is correct for the time of the query,
+                // so we need to update it to be relative to the current time
+                let ttl = record.ttl();
+                let ttl_expires_at = received_at + std::time::Duration::from_secs(ttl as u64);
+                let time_until_expiration = ttl_expires_at
+                    .duration_since(std::time::SystemTime::now())
+                    .unwrap_or_default();
+                record.set_ttl(time_until_expiration.as_secs() as u32);
+            }
+            TtlPolicy::Capped(ttl) => record.set_ttl(ttl),
+        }
 
         if record.record_type().is_soa() {
             d.soa.push(record);
@@ -112,10 +255,121 @@ fn sort_out_records(
     d
 }
 
+/// Whether an answer was actually DNSSEC-validated, as opposed to `--dnssec` merely being on:
+/// an insecure (unsigned) delegation resolves successfully under `opts.validate` without ever
+/// being signed, so the presence of an RRSIG is what actually distinguishes a validated answer.
+///
+/// `records` should be the full, unfiltered answer as returned by the resolver: the RRSIG
+/// covering it rides along in the same record set (same owner name as what it signs), so
+/// whoever caches `records` verbatim — which is exactly what happens below, since `content_json`
+/// is built from the same resolver answer this is checked against — keeps the signature and can
+/// replay it on a later cache hit instead of just asserting AD=1 with nothing to back it up.
+/// NSEC/NSEC3/DNSKEY aren't part of that answer set: they're consumed internally by the
+/// resolver's validator to produce it and the high-level `AsyncResolver`/`Lookup` API this
+/// handler is built on doesn't expose them, so there's nothing to capture there.
+fn is_authentic<'a>(dnssec_enabled: bool, mut records: impl Iterator<Item = &'a Record>) -> bool {
+    dnssec_enabled && records.any(|r| r.record_type() == RecordType::RRSIG)
+}
+
+fn now_unix() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// A cached answer, as stored in the `record` table.
+struct CachedAnswer {
+    records: Vec<Record>,
+    data_received_at_unix: i64,
+    /// Whether this record set was DNSSEC-validated when it was fetched. Carried from
+    /// [`cache::UpsertJob::authentic_data`] and replayed as-is on every hit, so a stale entry
+    /// can't claim authentication the current `--dnssec` setting didn't earn it.
+    authentic_data: bool,
+}
+
+/// Looks up a cached answer for `(record_name, record_type)`. Returns `None` on a true cache
+/// miss.
+///
+/// This is a plain read, on the hot path of every incoming query: it must not go through the
+/// writer pool. `last_query_at_unix` is bumped as a best-effort, fire-and-forget
+/// [`cache::WriterJob::Touch`] on a hit instead of being updated inline, so a flood of cache hits
+/// can't pile concurrent writers onto the SQLite pool, which is what the single writer task
+/// ([`cache::spawn_writer`]) exists to prevent.
+async fn fetch_cached(
+    db: &sqlx::SqlitePool,
+    cache_writer: &tokio::sync::mpsc::Sender<cache::WriterJob>,
+    record_name: &str,
+    record_type: &str,
+) -> Option<CachedAnswer> {
+    let item = sqlx::query!(
+        "SELECT content_json, data_received_at_unix, authentic_data FROM record WHERE record_name = ? AND record_type = ?",
+        record_name,
+        record_type
+    )
+    .fetch_optional(db)
+    .await
+    .expect("Failed to fetch record");
+
+    if item.is_some() {
+        if let Err(e) = cache_writer.try_send(cache::WriterJob::Touch(cache::TouchJob {
+            record_name: record_name.to_string(),
+            record_type: record_type.to_string(),
+            last_query_at_unix: now_unix(),
+        })) {
+            tracing::warn!(
+                "Cache writer is falling behind, dropping last-queried bump for {record_name} {record_type}: {e}"
+            );
+        }
+    }
+
+    item.map(|data| CachedAnswer {
+        records: serde_json::from_str(&data.content_json)
+            .expect("Failed to parse record content in database"),
+        data_received_at_unix: data.data_received_at_unix,
+        authentic_data: data.authentic_data,
+    })
+}
+
+/// The TTL (in seconds, possibly negative once expired) remaining on a cached answer, taken as
+/// the soonest-expiring record in it.
+fn remaining_ttl(records: &[Record], data_received_at_unix: i64, now_unix: i64) -> i64 {
+    records
+        .iter()
+        .map(|record| data_received_at_unix + record.ttl() as i64 - now_unix)
+        .min()
+        .unwrap_or(0)
+}
+
+/// How long a synthesized sinkhole answer is allowed to be cached by clients.
+const SINKHOLE_TTL: u32 = 60;
+
+/// Builds the answer record(s) for a `sinkhole` rule: a single A/AAAA record pointing at
+/// `address` if the query type matches its family, or no records (NODATA) otherwise.
+fn sinkhole_answer(request: &Request, address: IpAddr) -> Vec<Record> {
+    let query_type = request.query().query_type();
+    let data = match (query_type, address) {
+        (RecordType::A, IpAddr::V4(v4)) => RData::A(rdata::A(v4)),
+        (RecordType::AAAA, IpAddr::V6(v6)) => RData::AAAA(rdata::AAAA(v6)),
+        _ => return vec![],
+    };
+
+    let name = request
+        .query()
+        .name()
+        .to_string()
+        .parse()
+        .expect("query name should already be a valid domain name");
+    let mut record = Record::with(name, query_type, SINKHOLE_TTL);
+    record.set_data(Some(data));
+    vec![record]
+}
+
 impl RecordsByKind {
     pub fn make_response<'a>(
         &'a self,
         request: &'a Request,
+        authentic_data: bool,
     ) -> MessageResponse<
         'a,
         'a,
@@ -126,6 +380,7 @@ impl RecordsByKind {
     > {
         let mut header = Header::new();
         header.set_id(request.id());
+        header.set_authentic_data(authentic_data);
 
         header.set_answer_count(self.answers.len() as u16);
         header.set_name_server_count(self.name_servers.len() as u16);
@@ -149,6 +404,12 @@ impl RequestHandler for Handler {
         request: &Request,
         mut response_handle: R,
     ) -> ResponseInfo {
+        let _permit = self
+            .inflight
+            .acquire()
+            .await
+            .expect("Inflight semaphore should never be closed");
+
         match request.message_type() {
             hickory_server::proto::op::MessageType::Response => {
                 tracing::warn!("Received a response message from client: {request:?}");
@@ -165,50 +426,147 @@ impl RequestHandler for Handler {
             }
             hickory_server::proto::op::MessageType::Query => {
                 tracing::debug!("Received query from client: {request:?}");
-                // Try sending this query to the upstream DNS server
                 let query = request.query();
+
+                // Check the filtering rules before touching the upstream resolver or the cache
+                if let Some(rules) = &self.rules {
+                    match rules.lookup(&query.name().to_string()) {
+                        Some(filter::Action::Block) => {
+                            tracing::debug!("Blocking query for {}", query.name());
+                            let resp = MessageResponseBuilder::from_message_request(request);
+                            let mut header = Header::new();
+                            header.set_id(request.id());
+                            let msg = resp.error_msg(
+                                &header,
+                                hickory_server::proto::op::ResponseCode::NXDomain,
+                            );
+                            return response_handle
+                                .send_response(msg)
+                                .await
+                                .expect("Failed to send response to blocked query");
+                        }
+                        Some(filter::Action::Sinkhole(address)) => {
+                            tracing::debug!("Sinkholing query for {}", query.name());
+                            let answers = sinkhole_answer(request, address);
+                            let msg = RecordsByKind {
+                                answers,
+                                ..Default::default()
+                            }
+                            .make_response(request, false);
+                            return response_handle
+                                .send_response(msg)
+                                .await
+                                .expect("Failed to send response to sinkholed query");
+                        }
+                        Some(filter::Action::Allow) | None => {}
+                    }
+                }
+
+                let record_name = query.name().to_string();
+                let record_type = query.query_type().to_string();
+
+                // Cache-first: a fresh cached answer is served without ever touching upstream,
+                // and an expired-but-present one is served stale (RFC 8767) while we refresh it
+                // in the background. Only a true cache miss blocks on upstream below.
+                if let Some(cached) =
+                    fetch_cached(&self.db, &self.cache_writer, &record_name, &record_type).await
+                {
+                    let remaining =
+                        remaining_ttl(&cached.records, cached.data_received_at_unix, now_unix());
+
+                    if remaining > 0 {
+                        tracing::debug!("Serving {record_name} {record_type} from cache");
+                        if remaining < self.prefetch_threshold as i64 {
+                            tracing::debug!(
+                                "Prefetching {record_name} {record_type}, only {remaining}s of TTL left"
+                            );
+                            self.spawn_background_refresh(
+                                query
+                                    .name()
+                                    .to_string()
+                                    .parse()
+                                    .expect("query name should already be a valid domain name"),
+                                query.query_type(),
+                            );
+                        }
+
+                        let received_at = std::time::SystemTime::UNIX_EPOCH
+                            + std::time::Duration::from_secs(cached.data_received_at_unix as u64);
+                        let msg = sort_out_records(
+                            request,
+                            &cached.records,
+                            TtlPolicy::RelativeTo(received_at),
+                        );
+                        let msg = msg.make_response(request, cached.authentic_data);
+
+                        return response_handle
+                            .send_response(msg)
+                            .await
+                            .expect("Failed to send response from cache");
+                    }
+
+                    tracing::debug!(
+                        "Serving {record_name} {record_type} stale while refreshing in the background"
+                    );
+                    self.spawn_background_refresh(
+                        query
+                            .name()
+                            .to_string()
+                            .parse()
+                            .expect("query name should already be a valid domain name"),
+                        query.query_type(),
+                    );
+
+                    let msg = sort_out_records(
+                        request,
+                        &cached.records,
+                        TtlPolicy::Capped(self.serve_stale_ttl),
+                    );
+                    let msg = msg.make_response(request, cached.authentic_data);
+
+                    return response_handle
+                        .send_response(msg)
+                        .await
+                        .expect("Failed to send stale response from cache");
+                }
+
+                // True cache miss: block on the upstream DNS server
                 let resp = self.resolver.lookup(query.name(), query.query_type()).await;
                 match resp {
                     Ok(data) => {
                         tracing::debug!("Received response from upstream: {data:?}");
 
-                        // Record the response in the database for caching
-                        tokio::spawn({
-                            let db = self.db.clone();
-                            let query = query.clone();
-                            let data = data.clone();
+                        let authentic_data = is_authentic(self.dnssec, data.record_iter());
 
-                            let record_name = query.name().to_string();
-                            let record_type = query.query_type().to_string();
-                            let record_data =
+                        // Queue the response for the cache writer task; if it's fallen behind,
+                        // drop the update rather than blocking the response on it.
+                        {
+                            let content_json =
                                 serde_json::to_string(&data.record_iter().collect::<Vec<_>>())
                                     .expect("failed to serialize record data");
+                            let now = now_unix();
 
-                            let last_query_at_unix = std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs()
-                                as i64;
-                            let data_received_at_unix = last_query_at_unix;
-
-                            async move {
-                                sqlx::query!("INSERT INTO record (record_name, record_type, content_json, data_received_at_unix, last_query_at_unix) VALUES (?,?,?,?,?) ON CONFLICT (record_name, record_type) DO UPDATE SET content_json = ?, data_received_at_unix = ?, last_query_at_unix = ?",
-                                    record_name,
-                                    record_type,
-                                    record_data,
-                                    data_received_at_unix,
-                                    last_query_at_unix,
-                                    record_data,
-                                    data_received_at_unix,
-                                    last_query_at_unix,
-                                ).execute(&db).await.expect("Failed to insert record")
+                            if let Err(e) = self.cache_writer.try_send(cache::WriterJob::Upsert(
+                                cache::UpsertJob {
+                                    record_name: record_name.clone(),
+                                    record_type: record_type.clone(),
+                                    content_json,
+                                    data_received_at_unix: now,
+                                    last_query_at_unix: now,
+                                    authentic_data,
+                                },
+                            )) {
+                                tracing::warn!(
+                                    "Cache writer is falling behind, dropping update for {record_name} {record_type}: {e}"
+                                );
                             }
-                        });
+                        }
 
                         let records = data.records();
                         let received_at = std::time::SystemTime::now();
-                        let msg = sort_out_records(request, records, received_at);
-                        let msg = msg.make_response(request);
+                        let msg =
+                            sort_out_records(request, records, TtlPolicy::RelativeTo(received_at));
+                        let msg = msg.make_response(request, authentic_data);
 
                         return response_handle
                             .send_response(msg)
@@ -267,28 +625,22 @@ impl RequestHandler for Handler {
                         };
                         tracing::warn!("Failed to query upstream DNS server: {}", why);
 
-                        // Try fetching the response from the cache
-                        let now = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs() as i64;
-                        let record_name = query.name().to_string();
-                        let record_type = query.query_type().to_string();
-                        let item = sqlx::query!("UPDATE record SET last_query_at_unix = ? WHERE record_name = ? AND record_type = ? RETURNING content_json, data_received_at_unix",
-                            now,
-                            record_name,
-                            record_type
-                        ).fetch_optional(&self.db).await.expect("Failed to fetch record");
-
-                        if let Some(data) = item {
-                            let records: Vec<Record> = serde_json::from_str(&data.content_json)
-                                .expect("Failed to parse record content in database");
+                        // We already checked the cache before trying upstream and found nothing,
+                        // but check again: another in-flight request may have just populated it.
+                        let item =
+                            fetch_cached(&self.db, &self.cache_writer, &record_name, &record_type)
+                                .await;
 
+                        if let Some(cached) = item {
                             let received_at = std::time::SystemTime::UNIX_EPOCH
-                                + std::time::Duration::from_secs(data.data_received_at_unix as u64);
+                                + std::time::Duration::from_secs(cached.data_received_at_unix as u64);
 
-                            let msg = sort_out_records(request, &records, received_at);
-                            let msg = msg.make_response(request);
+                            let msg = sort_out_records(
+                                request,
+                                &cached.records,
+                                TtlPolicy::RelativeTo(received_at),
+                            );
+                            let msg = msg.make_response(request, cached.authentic_data);
 
                             return response_handle
                                 .send_response(msg)